@@ -0,0 +1,100 @@
+//! 按所属上下文管理一批孢子的容器。
+//!
+//! 「资源-孢子」这对抽象本身只负责让单个孢子在上下文间安全流转，
+//! 面对同时持有多个上下文、每个上下文上又有一批资源的应用程序，
+//! 调用者仍然要自己记住某个孢子究竟是哪个上下文申请的。
+//! [`SporeBank`] 按照 [`ContextSpore::ctx_raw`] 把孢子分桶存放，
+//! 从而可以批量地对属于同一个上下文的孢子进行操作。
+
+use crate::{AsRaw, ContextSpore};
+use alloc::vec::Vec;
+
+/// 按所属上下文分桶存放孢子的容器。
+pub struct SporeBank<Ctx: AsRaw, S: ContextSpore<Ctx>> {
+    buckets: Vec<(Ctx::Raw, Vec<S>)>,
+}
+
+impl<Ctx: AsRaw, S: ContextSpore<Ctx>> Default for SporeBank<Ctx, S> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Ctx: AsRaw, S: ContextSpore<Ctx>> SporeBank<Ctx, S> {
+    /// 创建一个空的孢子仓库。
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            buckets: Vec::new(),
+        }
+    }
+
+    fn bucket_mut(&mut self, raw: Ctx::Raw) -> &mut Vec<S>
+    where
+        Ctx::Raw: PartialEq,
+    {
+        let i = match self.buckets.iter().position(|(r, _)| *r == raw) {
+            Some(i) => i,
+            None => {
+                self.buckets.push((raw, Vec::new()));
+                self.buckets.len() - 1
+            }
+        };
+        &mut self.buckets[i].1
+    }
+
+    /// 存入一个孢子，根据它的 [`ctx_raw`](ContextSpore::ctx_raw) 归入对应的桶。
+    pub fn insert(&mut self, spore: S)
+    where
+        Ctx::Raw: PartialEq,
+    {
+        let raw = spore.ctx_raw();
+        self.bucket_mut(raw).push(spore);
+    }
+
+    /// 借出属于已加载上下文 `ctx` 的所有孢子的资源引用。
+    ///
+    /// 不属于 `ctx` 的孢子不会出现在结果中，也不会被触碰。
+    pub fn sprout_all<'ctx>(&'ctx self, ctx: &'ctx Ctx) -> Vec<&'ctx S::Resource<'ctx>>
+    where
+        Ctx: 'ctx,
+        Ctx::Raw: PartialEq,
+    {
+        let raw = unsafe { ctx.as_raw() };
+        self.buckets
+            .iter()
+            .find(|(r, _)| *r == raw)
+            .map(|(_, spores)| spores.iter().map(|s| s.sprout_ref(ctx)).collect())
+            .unwrap_or_default()
+    }
+
+    /// 取出并转换属于已加载上下文 `ctx` 的所有孢子，使它们在调用者手中重新成为资源，
+    /// 以便在上下文被销毁前正确地释放。
+    ///
+    /// 不属于 `ctx` 的孢子留在仓库中，不受影响。
+    pub fn take<'ctx>(&mut self, ctx: &'ctx Ctx) -> Vec<S::Resource<'ctx>>
+    where
+        Ctx: 'ctx,
+        Ctx::Raw: PartialEq,
+    {
+        let raw = unsafe { ctx.as_raw() };
+        let spores = match self.buckets.iter().position(|(r, _)| *r == raw) {
+            Some(i) => self.buckets.remove(i).1,
+            None => Vec::new(),
+        };
+        spores.into_iter().map(|s| s.sprout(ctx)).collect()
+    }
+}
+
+impl<Ctx: AsRaw, S: ContextSpore<Ctx>> Drop for SporeBank<Ctx, S> {
+    fn drop(&mut self) {
+        // 仓库本身不持有任何绕过孢子惯用法的资源，真正的“绝不泄露”保证来自每个孢子自身的
+        // `Drop`（见孤儿队列）。但在正常使用中，仓库应当先用 `take` 清空再销毁——
+        // 残留到这里通常意味着调用者忘记在对应上下文上归还孢子。
+        debug_assert!(
+            self.buckets.iter().all(|(_, spores)| spores.is_empty()),
+            "SporeBank dropped while still holding spores; drain it with `take` first"
+        );
+    }
+}
@@ -0,0 +1,52 @@
+//! 让资源安全地跨越 `.await` 悬挂点。
+//!
+//! [`ContextResource<'ctx, Ctx>`] 把资源的生命周期绑定在某个上下文的借用上，
+//! 而一次 `.await` 既可能让执行器把 `Future` 挪到别的线程上恢复，也可能让对应的上下文
+//! 在悬挂期间被换出、换入——两者都会让资源本身这种非 `'static`、非 `Send` 的值无法安全地
+//! 留在 `Future` 里跨越悬挂点。[`SporeGuard`] 提供了一条绕开这个限制的路：悬挂前把资源
+//! 孢子化成 `Send + Sync` 的形式存放起来，恢复后借助 [`try_sprout`](ContextSpore::try_sprout)
+//! 重新长成资源。
+//!
+//! 用法是两步式的：
+//!
+//! ```ignore
+//! let guard = SporeGuard::new(resource);
+//! let guard = some_future_holding(guard).await;
+//! let resource = guard.reacquire(&ctx)?;
+//! ```
+//!
+//! 注意上下文引用 `&ctx` 只在 `.await` 结束之后才会用到——绝不能把它提前传入、
+//! 让它混进跨越 `.await` 悬挂点的那部分状态里。上下文（例如 `CurrentCtx`）本身通常不是
+//! `Sync` 的，一旦 `&ctx` 被悬挂点之前的代码捕获，整个 `Future` 就会失去 `Send`，
+//! 这恰恰是 [`SporeGuard`] 想要避免的问题。
+
+use crate::{AsRaw, ContextResource, ContextSpore, WrongContext};
+
+/// 持有一个孢子化资源、可以安全跨越 `.await` 的守卫。
+pub struct SporeGuard<S>(S);
+
+impl<S> SporeGuard<S> {
+    /// 把资源孢子化，装入守卫，以便安全地跨越 `.await`。
+    #[inline]
+    pub fn new<'ctx, Ctx>(resource: S::Resource<'ctx>) -> Self
+    where
+        Ctx: AsRaw,
+        S: ContextSpore<Ctx>,
+        S::Resource<'ctx>: ContextResource<'ctx, Ctx, Spore = S>,
+    {
+        Self(resource.sporulate())
+    }
+
+    /// 在上下文重新可用后，把孢子重新长成资源。
+    ///
+    /// 如果 `ctx` 与孢子所属的上下文不匹配，返回的 [`WrongContext`] 带回孢子本身，
+    /// 所有权不会丢失，调用者可以换成正确的上下文重试。
+    #[inline]
+    pub fn reacquire<'ctx, Ctx>(self, ctx: &'ctx Ctx) -> Result<S::Resource<'ctx>, WrongContext<S>>
+    where
+        Ctx: AsRaw,
+        S: ContextSpore<Ctx>,
+    {
+        self.0.try_sprout(ctx)
+    }
+}
@@ -10,10 +10,27 @@
 #![no_std]
 #![deny(warnings, missing_docs)]
 
+extern crate alloc;
+// 宏生成的代码（包括下面测试里对 `impl_spore!` 的调用）使用未加限定的 `std::` 路径，
+// 依赖调用侧 crate 链接 `std`；这个 crate 自己在跑测试时也是调用侧。
+#[cfg(test)]
+extern crate std;
+
+/// 孢子被动析构时的延迟回收机制，见 [`drain_orphans`]。
+pub mod orphan;
+/// 按所属上下文管理一批孢子的容器，见 [`SporeBank`]。
+pub mod bank;
+/// 让资源安全地跨越 `.await` 悬挂点，见 [`SporeGuard`]。
+pub mod guard;
+
+pub use bank::SporeBank;
+pub use guard::SporeGuard;
+pub use orphan::drain_orphans;
+
 /// 资源的原始形式的表示。通常来自底层库的定义。
 pub trait AsRaw {
-    /// 原始形式的类型。
-    type Raw: Unpin + 'static;
+    /// 原始形式的类型。通常是一个可以随意复制的小句柄（例如指针或整数 id）。
+    type Raw: Copy + Unpin + 'static;
     /// # Safety
     ///
     /// The caller must ensure that the returned item is dropped before the original item.
@@ -26,7 +43,7 @@ pub trait AsRaw {
 /// 处于上下文资源状态的资源对象可以参与相应的功能。例如，处于上下文资源状态的存储区域可以读写。
 /// 但是业务逻辑中，不可避免地会出现需要暂时切换当前上下文而不释放资源的情况，
 /// 因此资源提供 [`sporulate`](ContextResource::sporulate) 方法将资源转换为孢子。
-pub trait ContextResource<'ctx, Ctx> {
+pub trait ContextResource<'ctx, Ctx: AsRaw> {
     /// 上下文资源对应的孢子类型。
     ///
     /// 这个约束保证了资源与孢子一一对应。
@@ -49,8 +66,15 @@ pub trait ContextResource<'ctx, Ctx> {
 /// 只有当申请这些资源的上下文被换回，并在上下文上将孢子恢复为资源后才能继续发挥作用。
 /// 上下文孢子提供 [`sprout`](ContextSpore::sprout) 方法将孢子转换为资源，
 /// 以及 [`sprout_ref`](ContextSpore::sprout_ref) 和 [`sprout_mut`](ContextSpore::sprout_mut) 方法获取资源的不可变和可变引用。
-/// 这些方法将引入运行时检查以保证孢子在正确的上下文上复原。
-pub trait ContextSpore<Ctx>: 'static + Send + Sync {
+/// 这些方法将引入运行时检查以保证孢子在正确的上下文上复原，一旦检查失败就会 panic。
+///
+/// 对于需要在多个上下文间流转、事先无法保证孢子一定属于当前上下文的场景，
+/// 每个方法都配有一个 `try_` 版本，用 [`Result`] 取代 panic：
+/// [`try_sprout`](ContextSpore::try_sprout) 在上下文不匹配时把孢子本身原样放回错误中，
+/// 不丢失所有权，因而可以在正确的上下文上重试；
+/// [`try_sprout_ref`](ContextSpore::try_sprout_ref) 和 [`try_sprout_mut`](ContextSpore::try_sprout_mut)
+/// 则返回 [`WrongContextError`]。panic 版本就是在这些方法之上实现的：结果为 `Err` 时直接 panic。
+pub trait ContextSpore<Ctx: AsRaw>: 'static + Send + Sync {
     /// 上下文孢子对应的资源类型。
     ///
     /// 这个约束保证了资源与孢子一一对应。
@@ -58,49 +82,103 @@ pub trait ContextSpore<Ctx>: 'static + Send + Sync {
     where
         Ctx: 'ctx;
 
+    /// 这个孢子所属上下文的原始形式。
+    ///
+    /// 用于在持有多个上下文的孢子的场景中（参见 [`SporeBank`](crate::bank::SporeBank)）判断孢子的归属。
+    fn ctx_raw(&self) -> Ctx::Raw;
+
+    /// 尝试将孢子转换为资源。
+    ///
+    /// 如果孢子不属于已加载的目标上下文 `ctx`，则原样放回孢子的所有权，
+    /// 以便调用者在正确的上下文上重试。
+    fn try_sprout(self, ctx: &Ctx) -> Result<Self::Resource<'_>, WrongContext<Self>>
+    where
+        Self: Sized;
+
+    /// 尝试从孢子中借出资源的不可变引用。
+    ///
+    /// 如果孢子不属于已加载的目标上下文 `ctx`，则返回 [`WrongContextError`]。
+    fn try_sprout_ref<'ctx>(
+        &'ctx self,
+        ctx: &'ctx Ctx,
+    ) -> Result<&'ctx Self::Resource<'ctx>, WrongContextError>;
+
+    /// 尝试从孢子中借出资源的可变引用。
+    ///
+    /// 如果孢子不属于已加载的目标上下文 `ctx`，则返回 [`WrongContextError`]。
+    fn try_sprout_mut<'ctx>(
+        &'ctx mut self,
+        ctx: &'ctx Ctx,
+    ) -> Result<&'ctx mut Self::Resource<'ctx>, WrongContextError>;
+
     /// 将孢子转换为资源。
     ///
-    /// # Safety
+    /// # Panics
     ///
-    /// 这个转换的安全性来源于运行时检查孢子是否属于已加载的目标上下文。
-    fn sprout(self, ctx: &Ctx) -> Self::Resource<'_>;
+    /// 如果孢子不属于已加载的目标上下文，将会 panic。需要避免 panic 时请改用
+    /// [`try_sprout`](ContextSpore::try_sprout)。
+    #[inline]
+    fn sprout(self, ctx: &Ctx) -> Self::Resource<'_>
+    where
+        Self: Sized,
+    {
+        match self.try_sprout(ctx) {
+            Ok(resource) => resource,
+            Err(_) => panic!("spore does not belong to this context"),
+        }
+    }
 
     /// 从孢子中借出资源的不可变引用。
     ///
-    /// # Safety
+    /// # Panics
     ///
-    /// 这个转换的安全性来源于运行时检查孢子是否属于已加载的目标上下文。
-    fn sprout_ref<'ctx>(&'ctx self, ctx: &'ctx Ctx) -> &Self::Resource<'_>;
+    /// 如果孢子不属于已加载的目标上下文，将会 panic。需要避免 panic 时请改用
+    /// [`try_sprout_ref`](ContextSpore::try_sprout_ref)。
+    #[inline]
+    fn sprout_ref<'ctx>(&'ctx self, ctx: &'ctx Ctx) -> &'ctx Self::Resource<'ctx> {
+        self.try_sprout_ref(ctx)
+            .expect("spore does not belong to this context")
+    }
 
     /// 从孢子中借出资源的可变引用。
     ///
-    /// # Safety
+    /// # Panics
     ///
-    /// 这个转换的安全性来源于运行时检查孢子是否属于已加载的目标上下文。
-    fn sprout_mut<'ctx>(&'ctx mut self, ctx: &'ctx Ctx) -> &mut Self::Resource<'_>;
+    /// 如果孢子不属于已加载的目标上下文，将会 panic。需要避免 panic 时请改用
+    /// [`try_sprout_mut`](ContextSpore::try_sprout_mut)。
+    #[inline]
+    fn sprout_mut<'ctx>(&'ctx mut self, ctx: &'ctx Ctx) -> &'ctx mut Self::Resource<'ctx> {
+        self.try_sprout_mut(ctx)
+            .expect("spore does not belong to this context")
+    }
 }
 
+/// 在错误的上下文上尝试将孢子转换为资源时产生的错误。
+///
+/// 持有原来的孢子，因此不会丢失所有权，可以在正确的上下文上重新尝试。
+#[derive(Debug)]
+pub struct WrongContext<S>(pub S);
+
+/// 在错误的上下文上尝试从孢子借出资源引用时产生的错误。
+#[derive(Debug, Clone, Copy)]
+pub struct WrongContextError;
+
 /// 孢子惯用法。
 ///
 /// 所有孢子类型应该满足这些能力以跨越上下文。
 ///
-/// 宏提供 3 项能力：
+/// 宏提供 2 项能力：
 ///
 /// - 所有孢子具有 `Send`；
 /// - 所有孢子具有 `Sync`；
-/// - 孢子类型绝不能自动释放，必须在合适的时机转化为资源以释放回正确的硬件上下文。
-///   因此为孢子实现 `Drop` 并直接抛出异常以避免资源泄露；
+///
+/// 孢子的 `Drop` 行为（被动析构时登记进孤儿队列而不是直接抛出异常）由 [`impl_spore!`] 另行生成，
+/// 因为它需要知道孢子对应的资源和上下文类型才能正确回收。
 #[macro_export]
 macro_rules! spore_convention {
     ($spore:ty) => {
         unsafe impl Send for $spore {}
         unsafe impl Sync for $spore {}
-        impl Drop for $spore {
-            #[inline]
-            fn drop(&mut self) {
-                unreachable!("Never drop ContextSpore");
-            }
-        }
     };
 }
 
@@ -115,13 +193,44 @@ pub struct RawContainer<Ctx: Unpin + 'static, Rss: Unpin + 'static> {
 }
 
 /// 实现资源和孢子的惯用法。
+///
+/// `$ctx` 是这对资源-孢子所属的上下文类型，作为参数传入而不是固定写死，
+/// 因此同一个 crate 内可以用不同的 `impl_spore!` 调用共存多种互不相同的上下文类型，
+/// 例如 `impl_spore!(Buf and BufSpore by (MyCtx, raw_t))`。
+///
+/// 生成的资源类型 `$resource<'ctx>` 对 `'ctx` 是不变的（invariant）而不是协变的：
+/// 它持有一个 `fn(&'ctx $ctx) -> &'ctx $ctx` 形式的 `PhantomData`，而不是更常见、
+/// 也更宽松的 `PhantomData<&'ctx ()>`。如正典的子类型/型变规则所警示的那样，
+/// 对一个由运行时检查验证过的、与上下文借用绑定的句柄使用过于宽松的型变，
+/// 会让编译器允许把验证时的生命周期悄悄放宽，而资源的存在本身正是以那次验证为前提的；
+/// 不变性拒绝了这种放宽。
+///
+/// ```compile_fail
+/// # #[derive(PartialEq, Clone, Copy)]
+/// # struct RawCtx(usize);
+/// # struct MyCtx;
+/// # impl context_spore::AsRaw for MyCtx {
+/// #     type Raw = RawCtx;
+/// #     unsafe fn as_raw(&self) -> Self::Raw { RawCtx(0) }
+/// # }
+/// # impl MyCtx {
+/// #     unsafe fn from_raw(raw: &RawCtx) -> &Self { &*(raw as *const RawCtx as *const Self) }
+/// # }
+/// context_spore::impl_spore!(Buf and BufSpore by (MyCtx, ()));
+///
+/// // 如果 `Buf<'ctx>` 对 `'ctx` 是协变的，这个放宽生命周期的转换会被接受；
+/// // 不变性使它编译失败。
+/// fn shorten<'long: 'short, 'short>(long: Buf<'long>) -> Buf<'short> {
+///     long
+/// }
+/// ```
 #[macro_export]
 macro_rules! impl_spore {
     ($resource:ident and $spore:ident by ($ctx:ty, $rss:ty)) => {
         #[repr(transparent)]
         pub struct $resource<'ctx>(
             $crate::RawContainer<<$ctx as $crate::AsRaw>::Raw, $rss>,
-            std::marker::PhantomData<&'ctx ()>,
+            std::marker::PhantomData<fn(&'ctx $ctx) -> &'ctx $ctx>,
         );
 
         impl<'ctx> $resource<'ctx> {
@@ -131,40 +240,86 @@ macro_rules! impl_spore {
             }
         }
 
+        // 字段包在 `ManuallyDrop` 里，这样 `Drop::drop` 里手动读出 `RawContainer` 之后，
+        // 编译器就不会在 `drop` 返回时再对这个字段自动析构一次，
+        // 否则资源会在孤儿队列真正回收之前就被提前释放。
         #[repr(transparent)]
-        pub struct $spore($crate::RawContainer<<$ctx as $crate::AsRaw>::Raw, $rss>);
+        pub struct $spore(std::mem::ManuallyDrop<$crate::RawContainer<<$ctx as $crate::AsRaw>::Raw, $rss>>);
 
         $crate::spore_convention!($spore);
 
-        impl $crate::ContextSpore<CurrentCtx> for $spore {
+        impl Drop for $spore {
+            #[inline]
+            fn drop(&mut self) {
+                // SAFETY: `ManuallyDrop::take` 之后 `self.0` 不会再被访问，读出的 `RawContainer`
+                // 被立即移交孤儿队列，不会发生重复释放。
+                let container = unsafe { std::mem::ManuallyDrop::take(&mut self.0) };
+                // SAFETY: `reclaim` 把孤儿重建为这个孢子对应的资源类型后立即析构，与 `sprout` 的
+                // 转换方式一致。
+                unsafe {
+                    $crate::orphan::orphan::<$ctx, $rss>(container, |container, _ctx| {
+                        drop($resource(container, std::marker::PhantomData))
+                    })
+                }
+            }
+        }
+
+        impl $crate::ContextSpore<$ctx> for $spore {
             type Resource<'ctx> = $resource<'ctx>;
 
             #[inline]
-            fn sprout(self, ctx: &$ctx) -> Self::Resource<'_> {
-                assert_eq!(self.0.ctx, unsafe { <$ctx as $crate::AsRaw>::as_raw(ctx) });
+            fn ctx_raw(&self) -> <$ctx as $crate::AsRaw>::Raw {
+                self.0.ctx
+            }
+
+            #[inline]
+            fn try_sprout(self, ctx: &$ctx) -> Result<Self::Resource<'_>, $crate::WrongContext<Self>> {
+                if self.0.ctx != unsafe { <$ctx as $crate::AsRaw>::as_raw(ctx) } {
+                    return Err($crate::WrongContext(self));
+                }
                 // SAFETY: `transmute_copy` + `forget` 是手工实现移动语义。
                 // `RawContainer` 具有 `Unpin` 保证它的安全性。
                 let ans = unsafe { std::mem::transmute_copy(&self.0) };
                 std::mem::forget(self);
-                ans
+                Ok(ans)
             }
 
             #[inline]
-            fn sprout_ref<'ctx>(&'ctx self, ctx: &'ctx $ctx) -> &Self::Resource<'_> {
-                assert_eq!(self.0.ctx, unsafe { <$ctx as $crate::AsRaw>::as_raw(ctx) });
+            fn try_sprout_ref<'ctx>(
+                &'ctx self,
+                ctx: &'ctx $ctx,
+            ) -> Result<&'ctx Self::Resource<'ctx>, $crate::WrongContextError> {
+                if self.0.ctx != unsafe { <$ctx as $crate::AsRaw>::as_raw(ctx) } {
+                    return Err($crate::WrongContextError);
+                }
                 // SAFETY: 资源以引用的形式返回，因此在使用完成后不会释放。
-                unsafe { std::mem::transmute(&self.0) }
+                Ok(unsafe {
+                    std::mem::transmute::<
+                        &$crate::RawContainer<<$ctx as $crate::AsRaw>::Raw, $rss>,
+                        &'ctx $resource<'ctx>,
+                    >(&self.0)
+                })
             }
 
             #[inline]
-            fn sprout_mut<'ctx>(&'ctx mut self, ctx: &'ctx $ctx) -> &mut Self::Resource<'_> {
-                assert_eq!(self.0.ctx, unsafe { <$ctx as $crate::AsRaw>::as_raw(ctx) });
+            fn try_sprout_mut<'ctx>(
+                &'ctx mut self,
+                ctx: &'ctx $ctx,
+            ) -> Result<&'ctx mut Self::Resource<'ctx>, $crate::WrongContextError> {
+                if self.0.ctx != unsafe { <$ctx as $crate::AsRaw>::as_raw(ctx) } {
+                    return Err($crate::WrongContextError);
+                }
                 // SAFETY: 资源以可变引用的形式返回，因此在使用完成后不会释放。
-                unsafe { std::mem::transmute(&mut self.0) }
+                Ok(unsafe {
+                    std::mem::transmute::<
+                        &mut $crate::RawContainer<<$ctx as $crate::AsRaw>::Raw, $rss>,
+                        &'ctx mut $resource<'ctx>,
+                    >(&mut self.0)
+                })
             }
         }
 
-        impl<'ctx> $crate::ContextResource<'ctx, CurrentCtx> for $resource<'ctx> {
+        impl<'ctx> $crate::ContextResource<'ctx, $ctx> for $resource<'ctx> {
             type Spore = $spore;
 
             #[inline]
@@ -173,8 +328,204 @@ macro_rules! impl_spore {
                 // `RawContainer` 具有 `Unpin` 保证它的安全性。
                 let s = unsafe { std::mem::transmute_copy(&self.0) };
                 std::mem::forget(self);
-                $spore(s)
+                $spore(std::mem::ManuallyDrop::new(s))
             }
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{AsRaw, ContextResource, ContextSpore, RawContainer, SporeBank, WrongContext};
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub(crate) struct RawCtx(usize);
+
+    pub(crate) struct TestCtx(usize);
+
+    impl AsRaw for TestCtx {
+        type Raw = RawCtx;
+        #[inline]
+        unsafe fn as_raw(&self) -> RawCtx {
+            RawCtx(self.0)
+        }
+    }
+
+    impl TestCtx {
+        #[inline]
+        unsafe fn from_raw(raw: &RawCtx) -> &Self {
+            &*(raw as *const RawCtx).cast()
+        }
+    }
+
+    /// 持有一个指向外部 `AtomicBool` 的裸指针，析构时把它置位——
+    /// 用它在测试里观察资源到底有没有、什么时候真的被释放。
+    struct DropFlag(*const AtomicBool);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            unsafe { (*self.0).store(true, Ordering::SeqCst) }
+        }
+    }
+
+    crate::impl_spore!(Buf and BufSpore by (TestCtx, DropFlag));
+
+    #[test]
+    fn passive_drop_is_reclaimed_through_the_orphan_queue() {
+        let ctx = TestCtx(1);
+        let dropped = AtomicBool::new(false);
+        let raw = RawContainer {
+            ctx: unsafe { ctx.as_raw() },
+            rss: DropFlag(&dropped),
+        };
+        let spore = Buf(raw, core::marker::PhantomData).sporulate();
+
+        // 被动析构（没有经过 `sprout`）只登记进孤儿队列，不会立即释放资源。
+        drop(spore);
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        // 上下文重新成为当前上下文后，驱动调用 `drain_orphans` 才真正释放。
+        crate::drain_orphans(&ctx);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn try_sprout_checks_context_and_returns_spore_on_mismatch() {
+        let ctx_a = TestCtx(10);
+        let ctx_b = TestCtx(20);
+        let dropped = AtomicBool::new(false);
+        let raw = RawContainer {
+            ctx: unsafe { ctx_a.as_raw() },
+            rss: DropFlag(&dropped),
+        };
+        let spore = Buf(raw, core::marker::PhantomData).sporulate();
+
+        let spore = match spore.try_sprout(&ctx_b) {
+            Err(WrongContext(spore)) => spore,
+            Ok(_) => panic!("spore must not sprout on the wrong context"),
+        };
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        let resource = spore
+            .try_sprout(&ctx_a)
+            .unwrap_or_else(|_| panic!("spore must sprout on the context that owns it"));
+        drop(resource);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn try_sprout_ref_and_mut_check_context() {
+        let ctx_a = TestCtx(11);
+        let ctx_b = TestCtx(12);
+        let dropped = AtomicBool::new(false);
+        let raw = RawContainer {
+            ctx: unsafe { ctx_a.as_raw() },
+            rss: DropFlag(&dropped),
+        };
+        let mut spore = Buf(raw, core::marker::PhantomData).sporulate();
+
+        assert!(spore.try_sprout_ref(&ctx_b).is_err());
+        let resource = spore.try_sprout_ref(&ctx_a).unwrap();
+        assert_eq!(unsafe { resource.ctx().as_raw() }, unsafe { ctx_a.as_raw() });
+        assert!(spore.try_sprout_mut(&ctx_b).is_err());
+        assert!(spore.try_sprout_mut(&ctx_a).is_ok());
+
+        match spore.try_sprout(&ctx_a) {
+            Ok(resource) => drop(resource),
+            Err(_) => panic!("spore must sprout on the context that owns it"),
+        }
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn spore_bank_buckets_by_owning_context() {
+        let ctx_a = TestCtx(100);
+        let ctx_b = TestCtx(200);
+        let dropped_a = AtomicBool::new(false);
+        let dropped_b = AtomicBool::new(false);
+
+        let mut bank = SporeBank::<TestCtx, BufSpore>::new();
+        bank.insert(
+            Buf(
+                RawContainer {
+                    ctx: unsafe { ctx_a.as_raw() },
+                    rss: DropFlag(&dropped_a),
+                },
+                core::marker::PhantomData,
+            )
+            .sporulate(),
+        );
+        bank.insert(
+            Buf(
+                RawContainer {
+                    ctx: unsafe { ctx_b.as_raw() },
+                    rss: DropFlag(&dropped_b),
+                },
+                core::marker::PhantomData,
+            )
+            .sporulate(),
+        );
+
+        assert_eq!(bank.sprout_all(&ctx_a).len(), 1);
+        assert_eq!(bank.sprout_all(&ctx_b).len(), 1);
+
+        // 只取出属于 `ctx_a` 的孢子，`ctx_b` 的那个留在仓库里不受影响。
+        let taken = bank.take(&ctx_a);
+        assert_eq!(taken.len(), 1);
+        drop(taken);
+        assert!(dropped_a.load(Ordering::SeqCst));
+        assert!(!dropped_b.load(Ordering::SeqCst));
+
+        // 清空仓库，避免 `SporeBank::drop` 里的非空断言被触发。
+        drop(bank.take(&ctx_b));
+        assert!(dropped_b.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn spore_guard_reacquires_on_matching_context() {
+        let ctx = TestCtx(1001);
+        let dropped = AtomicBool::new(false);
+        let raw = RawContainer {
+            ctx: unsafe { ctx.as_raw() },
+            rss: DropFlag(&dropped),
+        };
+        let resource = Buf(raw, core::marker::PhantomData);
+
+        // `SporeGuard::new` 把资源孢子化，模拟携带它跨越一次 `.await` 悬挂点。
+        let guard: crate::SporeGuard<BufSpore> = crate::SporeGuard::new::<TestCtx>(resource);
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        let resource = guard
+            .reacquire(&ctx)
+            .unwrap_or_else(|_| panic!("guard must reacquire on the context it was sporulated from"));
+        assert!(!dropped.load(Ordering::SeqCst));
+        drop(resource);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn spore_guard_hands_spore_back_on_wrong_context() {
+        let ctx_a = TestCtx(1002);
+        let ctx_b = TestCtx(2002);
+        let dropped = AtomicBool::new(false);
+        let raw = RawContainer {
+            ctx: unsafe { ctx_a.as_raw() },
+            rss: DropFlag(&dropped),
+        };
+        let resource = Buf(raw, core::marker::PhantomData);
+
+        let guard: crate::SporeGuard<BufSpore> = crate::SporeGuard::new::<TestCtx>(resource);
+        let spore = match guard.reacquire(&ctx_b) {
+            Err(WrongContext(spore)) => spore,
+            Ok(_) => panic!("guard must not reacquire on the wrong context"),
+        };
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        let resource = spore
+            .try_sprout(&ctx_a)
+            .unwrap_or_else(|_| panic!("spore must sprout on the context it was sporulated from"));
+        drop(resource);
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+}
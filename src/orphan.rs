@@ -0,0 +1,179 @@
+//! 孢子被动析构时的延迟回收队列。
+//!
+//! 按照 [`spore_convention!`](crate::spore_convention) 的原始约定，孢子被直接析构是一个逻辑错误，
+//! 因为这意味着资源永远不会被正确地释放回申请它的上下文。但如果在栈展开（unwinding）过程中
+//! 才发生这种析构——例如某次 `panic` 导致持有孢子的调用栈被回收——再次 `panic` 会触发二次 `panic`，
+//! 使整个进程 `abort`，这比单纯的资源泄露更加糟糕。
+//!
+//! 因此孢子被动析构时不再直接抛出异常，而是将其原始容器连同重建资源所需的回收方法一起
+//! 登记到这个模块维护的孤儿队列中。驱动应在每次令某个上下文重新成为当前上下文之后调用
+//! [`drain_orphans`]，把所有属于这个上下文的孤儿孢子重新变回资源并立即析构，
+//! 从而把资源真正释放回上下文，同时保持“绝不悄悄泄露”的约定。
+
+use crate::{AsRaw, RawContainer};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::{Any, TypeId};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// 一个被遗弃的孤儿：保存着原始容器，以及把它重建为资源并释放所需的回收函数。
+trait OrphanEntry<Ctx: AsRaw> {
+    /// 这个孤儿所属上下文的原始形式，用于匹配 [`drain_orphans`] 的目标上下文。
+    fn ctx_raw(&self) -> &Ctx::Raw;
+    /// 将孤儿重建为资源并立即析构，从而把资源释放回 `ctx`。
+    ///
+    /// # Safety
+    ///
+    /// 调用者必须保证 `ctx` 确实是这个孤儿原本所属的、已加载的上下文。
+    unsafe fn reclaim(self: Box<Self>, ctx: &Ctx);
+}
+
+struct TypedOrphan<Ctx: AsRaw, Rss: Unpin + 'static> {
+    container: RawContainer<Ctx::Raw, Rss>,
+    reclaim: unsafe fn(RawContainer<Ctx::Raw, Rss>, &Ctx),
+}
+
+// SAFETY: 与 `spore_convention!` 中孢子的 `Send` 约定一致——
+// 孤儿队列本就是孢子析构路径的延伸，孢子本身已经被钦定为 `Send`。
+unsafe impl<Ctx: AsRaw, Rss: Unpin + 'static> Send for TypedOrphan<Ctx, Rss> {}
+
+impl<Ctx: AsRaw + 'static, Rss: Unpin + 'static> OrphanEntry<Ctx> for TypedOrphan<Ctx, Rss> {
+    #[inline]
+    fn ctx_raw(&self) -> &Ctx::Raw {
+        &self.container.ctx
+    }
+
+    #[inline]
+    unsafe fn reclaim(self: Box<Self>, ctx: &Ctx) {
+        (self.reclaim)(self.container, ctx)
+    }
+}
+
+/// 极简自旋锁，仅用于保护孤儿队列，避免在 `no_std` 环境下引入额外依赖。
+struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: 对 `value` 的访问总是经过 `locked` 互斥。
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn lock(&self) -> SpinGuard<'_, T> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        SpinGuard { lock: self }
+    }
+}
+
+struct SpinGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> core::ops::Deref for SpinGuard<'_, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        // SAFETY: 持有 `SpinGuard` 意味着持有锁。
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> core::ops::DerefMut for SpinGuard<'_, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: 持有 `SpinGuard` 意味着独占锁。
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinGuard<'_, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+/// 所有上下文类型共用的孤儿队列注册表，按 `Ctx` 的 [`TypeId`] 分桶。
+///
+/// 必须按类型分桶而不是放进同一个队列，因为不同上下文类型的原始形式互不相同。
+static REGISTRY: SpinLock<Vec<(TypeId, Box<dyn Any + Send>)>> = SpinLock::new(Vec::new());
+
+fn with_queue<Ctx, F, R>(f: F) -> R
+where
+    Ctx: AsRaw + 'static,
+    F: FnOnce(&mut Vec<Box<dyn OrphanEntry<Ctx> + Send>>) -> R,
+{
+    let mut table = REGISTRY.lock();
+    let ty = TypeId::of::<Ctx>();
+    if !table.iter().any(|(t, _)| *t == ty) {
+        let queue: Vec<Box<dyn OrphanEntry<Ctx> + Send>> = Vec::new();
+        table.push((ty, Box::new(queue)));
+    }
+    let (_, slot) = table.iter_mut().find(|(t, _)| *t == ty).unwrap();
+    let queue = slot
+        .downcast_mut::<Vec<Box<dyn OrphanEntry<Ctx> + Send>>>()
+        .unwrap();
+    f(queue)
+}
+
+/// 将一个被动析构的孤儿容器登记到队列中，等待 [`drain_orphans`] 回收。
+///
+/// `reclaim` 负责把 `container` 重建为对应的资源并立即将其析构。
+///
+/// # Safety
+///
+/// 调用者必须保证 `reclaim` 能够安全地把 `container` 转换回资源——
+/// 即 `container` 的来源和 `reclaim` 的实现对应同一种资源类型。
+pub unsafe fn orphan<Ctx, Rss>(
+    container: RawContainer<Ctx::Raw, Rss>,
+    reclaim: unsafe fn(RawContainer<Ctx::Raw, Rss>, &Ctx),
+) where
+    Ctx: AsRaw + 'static,
+    Rss: Unpin + 'static,
+{
+    let entry: Box<dyn OrphanEntry<Ctx> + Send> = Box::new(TypedOrphan { container, reclaim });
+    with_queue::<Ctx, _, _>(|queue| queue.push(entry));
+}
+
+/// 回收所有属于 `ctx` 的孤儿孢子：把它们重建为资源并立即析构，
+/// 从而把资源真正释放回这个上下文。
+///
+/// 驱动应在每次令 `ctx` 重新成为当前上下文之后调用本函数。
+pub fn drain_orphans<Ctx>(ctx: &Ctx)
+where
+    Ctx: AsRaw + 'static,
+    Ctx::Raw: PartialEq,
+{
+    // SAFETY: 只用于和已登记的孤儿比较，不涉及資源所有权。
+    let raw = unsafe { ctx.as_raw() };
+    let drained = with_queue::<Ctx, _, _>(|queue| {
+        let mut drained = Vec::new();
+        let mut i = 0;
+        while i < queue.len() {
+            if *queue[i].ctx_raw() == raw {
+                drained.push(queue.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        drained
+    });
+    for entry in drained {
+        // SAFETY: `ctx` 就是这些孤儿登记时所匹配的上下文，且已重新加载为当前上下文。
+        unsafe { entry.reclaim(ctx) };
+    }
+}